@@ -0,0 +1,60 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use toresy::Tokenizer;
+
+/// Builds a large synthetic source of repeated parenthesized forms mixing identifiers, numbers,
+/// symbols, and string literals, the mix the tokenizer sees in real rule-rewriting input.
+fn generate_input(repetitions: usize) -> String {
+    let mut input = String::new();
+    for i in 0..repetitions {
+        input.push_str(&format!(
+            "(define value_{i} (+ {i} 42) \"label {i}\")\n"
+        ));
+    }
+    input
+}
+
+/// Like [`generate_input`], but with identifiers that lead with a non-ASCII alphabetic scalar
+/// (`λ`, CJK ideographs) or carry accented Latin (`naïve`), and with the gap after `define`
+/// being a non-ASCII whitespace scalar (NBSP) every other repetition, so the bench also
+/// guards against the tokenizer spinning forever on a Unicode identifier start or a Unicode whitespace gap instead of
+/// round-tripping them.
+fn generate_unicode_input(repetitions: usize) -> String {
+    let mut input = String::new();
+    for i in 0..repetitions {
+        let sep = if i % 2 == 0 { "\u{00A0}" } else { " " };
+        input.push_str(&format!(
+            "(define{sep}λ_{i} (naïve 日本語_{i}) \"héllo wörld {i}\")\n"
+        ));
+    }
+    input
+}
+
+fn tokenize_all(input: &str) -> usize {
+    let mut tokenizer = Tokenizer::new(input);
+    let mut count = 0;
+    while let Ok(Some(token)) = tokenizer.next_token() {
+        black_box(token);
+        count += 1;
+    }
+    count
+}
+
+fn bench_tokenizer(c: &mut Criterion) {
+    let input = generate_input(10_000);
+    c.bench_function("tokenize_large_input", |b| {
+        b.iter(|| tokenize_all(black_box(&input)));
+    });
+}
+
+fn bench_tokenizer_unicode(c: &mut Criterion) {
+    let input = generate_unicode_input(10_000);
+    // Each repetition yields a fixed 9 tokens; a hung or mis-dispatching tokenizer would
+    // either never reach this assertion or return a different count.
+    assert_eq!(tokenize_all(&input), 10_000 * 9);
+    c.bench_function("tokenize_unicode_input", |b| {
+        b.iter(|| tokenize_all(black_box(&input)));
+    });
+}
+
+criterion_group!(benches, bench_tokenizer, bench_tokenizer_unicode);
+criterion_main!(benches);
@@ -1,56 +1,57 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
-pub enum QuoteStyle {
-    Single,
-    Double,
-}
+use toresy::Span;
 
-#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
-pub enum Token {
-    Identifier(String),
-    Number(String),
-    Symbol(String),
-    OpenParen(String),
-    CloseParen(String),
-    StringLiteral(String, QuoteStyle),
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+    Hint,
 }
 
-impl Token {
-    pub fn enum_type(&mut self) -> String {
-        match self {
-            Token::Identifier(_) => "Identifier",
-            Token::Number(_) => "Number",
-            Token::Symbol(_) => "Symbol",
-            Token::OpenParen(_) => "OpenParen",
-            Token::CloseParen(_) => "CloseParen",
-            Token::StringLiteral(_, _) => "StringLiteral",
+impl Severity {
+    #[must_use]
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "error" => Some(Severity::Error),
+            "warning" | "warn" => Some(Severity::Warning),
+            "info" => Some(Severity::Info),
+            "hint" => Some(Severity::Hint),
+            _ => None,
         }
-        .to_owned()
     }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Span>,
+    pub rule: Option<String>,
+}
 
-    pub fn value(&mut self) -> String {
-        match self {
-            Token::Identifier(s)
-            | Token::Number(s)
-            | Token::Symbol(s)
-            | Token::OpenParen(s)
-            | Token::CloseParen(s)
-            | Token::StringLiteral(s, _) => s,
+impl Diagnostic {
+    #[must_use]
+    pub fn new(severity: Severity, message: String) -> Self {
+        Self {
+            severity,
+            message,
+            span: None,
+            rule: None,
         }
-        .to_owned()
     }
 
-    pub fn quote_style(&mut self) -> Option<String> {
-        match self {
-            Token::StringLiteral(_, s) => Some(
-                match s {
-                    QuoteStyle::Single => "Single",
-                    QuoteStyle::Double => "Double",
-                }
-                .to_owned(),
-            ),
-            _ => None,
-        }
+    #[must_use]
+    pub fn info(message: String) -> Self {
+        Self::new(Severity::Info, message)
+    }
+
+    /// Tags this diagnostic with the name of the `rule` block that emitted it.
+    #[must_use]
+    pub fn with_rule(mut self, rule: String) -> Self {
+        self.rule = Some(rule);
+        self
     }
 }
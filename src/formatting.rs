@@ -1,10 +1,13 @@
-use crate::Token;
+use crate::{Diagnostic, Severity, Token};
 
 use std::any::TypeId;
 use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 use std::time::Instant;
 
+use aho_corasick::AhoCorasick;
+use regex::Regex;
 use rhai::packages::{CorePackage, Package};
 use rhai::{Array, Dynamic, Engine, EvalAltResult, ImmutableString, Map, FLOAT, INT};
 
@@ -179,8 +182,93 @@ fn script_array_contains(arr: Array, v: &Dynamic) -> bool {
         .any(|ele| script_value_equals(ele, v.clone()).unwrap_or_default())
 }
 
+fn error_to_runtime_error<E: std::fmt::Display>(err: E) -> Box<EvalAltResult> {
+    err.to_string().into()
+}
+
+/// Matches `s` against `pattern`, compiling (and caching) `pattern` as a regex on first use.
+fn script_matches(
+    cache: &Rc<RefCell<HashMap<String, Regex>>>,
+    s: &str,
+    pattern: &str,
+) -> ScriptResult<bool> {
+    let mut cache = cache.borrow_mut();
+    if !cache.contains_key(pattern) {
+        let re = Regex::new(pattern).map_err(error_to_runtime_error)?;
+        cache.insert(pattern.to_owned(), re);
+    }
+    Ok(cache[pattern].is_match(s))
+}
+
+fn script_contains_any(value: &str, patterns: Array) -> ScriptResult<bool> {
+    let patterns = patterns
+        .into_iter()
+        .map(Dynamic::into_string)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(error_to_runtime_error)?;
+    let ac = AhoCorasick::new(patterns).map_err(error_to_runtime_error)?;
+    Ok(ac.is_match(value))
+}
+
+fn script_exists(path: &str) -> bool {
+    std::path::Path::new(path).exists()
+}
+
+fn script_is_file(path: &str) -> bool {
+    std::path::Path::new(path).is_file()
+}
+
+fn script_is_dir(path: &str) -> bool {
+    std::path::Path::new(path).is_dir()
+}
+
+fn script_read_file(path: &str) -> ScriptResult<String> {
+    std::fs::read_to_string(path).map_err(error_to_runtime_error)
+}
+
+fn script_glob(pattern: &str) -> ScriptResult<Array> {
+    let paths = glob::glob(pattern).map_err(error_to_runtime_error)?;
+    paths
+        .map(|entry| entry.map(|path| Dynamic::from(path.display().to_string())))
+        .collect::<Result<Array, _>>()
+        .map_err(error_to_runtime_error)
+}
+
+/// Builds the `Map` exposed to scripts as `tok.span`, with `start`/`end`/`line`/`col` entries.
+#[allow(clippy::cast_possible_wrap)]
+fn token_span_map(t: &mut Token) -> Map {
+    let span = t.span();
+    let mut map = Map::new();
+    map.insert("start".into(), Dynamic::from(span.start as INT));
+    map.insert("end".into(), Dynamic::from(span.end as INT));
+    map.insert("line".into(), Dynamic::from(INT::from(span.line)));
+    map.insert("col".into(), Dynamic::from(INT::from(span.col)));
+    map
+}
+
+fn diagnostic_from_map(m: &Map) -> ScriptResult<Diagnostic> {
+    let msg = m
+        .get("msg")
+        .ok_or_else(|| error_to_runtime_error("emit map is missing required field \"msg\""))?
+        .clone()
+        .into_string()
+        .map_err(error_to_runtime_error)?;
+    let severity = m
+        .get("severity")
+        .and_then(|e| e.clone().into_string().ok())
+        .and_then(|s| Severity::parse(&s))
+        .unwrap_or(Severity::Info);
+    Ok(Diagnostic::new(severity, msg))
+}
+
 #[allow(clippy::too_many_lines)]
-pub fn build_engine(messages: Rc<RefCell<Vec<String>>>, debug: bool) -> Engine {
+pub fn build_engine(
+    messages: Rc<RefCell<Vec<Diagnostic>>>,
+    rule_names: Rc<RefCell<HashSet<String>>>,
+    state: Rc<RefCell<Map>>,
+    debug: bool,
+    allow_fs: bool,
+) -> Engine {
     let mut engine = Engine::new();
     engine.set_max_expr_depths(128, 64);
 
@@ -253,7 +341,36 @@ pub fn build_engine(messages: Rc<RefCell<Vec<String>>>, debug: bool) -> Engine {
         .register_fn("ends_with", script_ends_with)
         .register_fn("trim", script_trim)
         .register_fn("is_string", script_is_no_string)
-        .register_fn("is_string", script_is_string);
+        .register_fn("is_string", script_is_string)
+        .register_fn("contains_any", script_contains_any);
+
+    {
+        let regex_cache: Rc<RefCell<HashMap<String, Regex>>> =
+            Rc::new(RefCell::new(HashMap::new()));
+        engine
+            .register_custom_operator("matches", 15)
+            .unwrap()
+            .register_fn("matches", move |s: &str, pattern: &str| {
+                script_matches(&regex_cache, s, pattern)
+            });
+    }
+
+    // `get_state`/`set_state` give rules a place to accumulate data across the token stream
+    // (and, via --state-in/--state-out, across separate runs) by going through the shared
+    // `Rc<RefCell<Map>>` rather than a `Map` value copied into the Rhai scope, which a script
+    // could reassign without the write ever reaching what `--state-out` serializes.
+    {
+        let state = state.clone();
+        engine.register_fn("get_state", move |key: &str| -> Dynamic {
+            state.borrow().get(key).cloned().unwrap_or(Dynamic::UNIT)
+        });
+    }
+    {
+        let state = state.clone();
+        engine.register_fn("set_state", move |key: &str, value: Dynamic| {
+            state.borrow_mut().insert(key.into(), value);
+        });
+    }
 
     // DSL
     engine
@@ -289,7 +406,7 @@ pub fn build_engine(messages: Rc<RefCell<Vec<String>>>, debug: bool) -> Engine {
             {
                 let messages = messages.clone();
                 engine.register_fn("-", move |msg: $T| {
-                    messages.borrow_mut().push(format!("{msg}"));
+                    messages.borrow_mut().push(Diagnostic::info(format!("{msg}")));
                 });
             }
             )*
@@ -304,8 +421,8 @@ pub fn build_engine(messages: Rc<RefCell<Vec<String>>>, debug: bool) -> Engine {
             {
                 let messages = messages.clone();
                 engine.register_fn("++", move |a: $A, b: $B| {
-                    messages.borrow_mut().push(format!("{a}"));
-                    messages.borrow_mut().push(format!("{b}"));
+                    messages.borrow_mut().push(Diagnostic::info(format!("{a}")));
+                    messages.borrow_mut().push(Diagnostic::info(format!("{b}")));
                 });
             }
             )*
@@ -358,13 +475,13 @@ pub fn build_engine(messages: Rc<RefCell<Vec<String>>>, debug: bool) -> Engine {
         ($($T: ty),*) => {$({
             let messages = messages.clone();
             engine.register_fn("++", move |a: $T, _b: ()| {
-                messages.borrow_mut().push(a.to_string());
+                messages.borrow_mut().push(Diagnostic::info(a.to_string()));
             });
         }
         {
             let messages = messages.clone();
             engine.register_fn("++", move |_a: (), b: $T| {
-                messages.borrow_mut().push(b.to_string());
+                messages.borrow_mut().push(Diagnostic::info(b.to_string()));
             });
         }
         )*};
@@ -374,22 +491,22 @@ pub fn build_engine(messages: Rc<RefCell<Vec<String>>>, debug: bool) -> Engine {
         ($($T: ty),*) => {$({
             let messages = messages.clone();
             engine.register_fn("++", move |a: $T, b: &str| {
-                messages.borrow_mut().push(a.to_string());
-                messages.borrow_mut().push(b.to_owned());
+                messages.borrow_mut().push(Diagnostic::info(a.to_string()));
+                messages.borrow_mut().push(Diagnostic::info(b.to_owned()));
             });
         }
         {
             let messages = messages.clone();
             engine.register_fn("++", move |a: &str, b: $T| {
-                messages.borrow_mut().push(a.to_owned());
-                messages.borrow_mut().push(b.to_string());
+                messages.borrow_mut().push(Diagnostic::info(a.to_owned()));
+                messages.borrow_mut().push(Diagnostic::info(b.to_string()));
             });
         }
         {
             let messages = messages.clone();
             engine.register_fn("++", move |a: $T, b: $T| {
-                messages.borrow_mut().push(a.to_string());
-                messages.borrow_mut().push(b.to_string());
+                messages.borrow_mut().push(Diagnostic::info(a.to_string()));
+                messages.borrow_mut().push(Diagnostic::info(b.to_string()));
             });
         })*};
     }
@@ -398,22 +515,22 @@ pub fn build_engine(messages: Rc<RefCell<Vec<String>>>, debug: bool) -> Engine {
         ($($T: ty),*) => {$({
             let messages = messages.clone();
             engine.register_fn("++", move |a: &Vec<$T>, b: &str| {
-                messages.borrow_mut().push(format!("{:?}", a));
-                messages.borrow_mut().push(b.to_owned());
+                messages.borrow_mut().push(Diagnostic::info(format!("{:?}", a)));
+                messages.borrow_mut().push(Diagnostic::info(b.to_owned()));
             });
         }
         {
             let messages = messages.clone();
             engine.register_fn("++", move |a: &str, b: &Vec<$T>| {
-                messages.borrow_mut().push(a.to_owned());
-                messages.borrow_mut().push(format!("{:?}", b));
+                messages.borrow_mut().push(Diagnostic::info(a.to_owned()));
+                messages.borrow_mut().push(Diagnostic::info(format!("{:?}", b)));
             });
         }
         {
             let messages = messages.clone();
             engine.register_fn("++", move |a: &Vec<$T>, b: &Vec<$T>| {
-                messages.borrow_mut().push(format!("{:?}", a));
-                messages.borrow_mut().push(format!("{:?}", b));
+                messages.borrow_mut().push(Diagnostic::info(format!("{:?}", a)));
+                messages.borrow_mut().push(Diagnostic::info(format!("{:?}", b)));
             });
         })*};
     }
@@ -431,20 +548,20 @@ pub fn build_engine(messages: Rc<RefCell<Vec<String>>>, debug: bool) -> Engine {
     {
         let messages = messages.clone();
         engine.register_fn("++", move |(): (), b: &str| {
-            messages.borrow_mut().push(b.to_owned());
+            messages.borrow_mut().push(Diagnostic::info(b.to_owned()));
         });
     }
     {
         let messages = messages.clone();
         engine.register_fn("++", move |(): (), b: usize| {
-            messages.borrow_mut().push(b.to_string());
+            messages.borrow_mut().push(Diagnostic::info(b.to_string()));
         });
     }
     engine.register_custom_operator("++", 15).unwrap();
     {
         let messages = messages.clone();
         engine.register_fn("emit", move |msg: &str| {
-            messages.borrow_mut().push(msg.to_owned());
+            messages.borrow_mut().push(Diagnostic::info(msg.to_owned()));
         });
     }
     engine.register_custom_operator("then_emit", 15).unwrap();
@@ -452,22 +569,18 @@ pub fn build_engine(messages: Rc<RefCell<Vec<String>>>, debug: bool) -> Engine {
         let messages = messages.clone();
         engine.register_fn("then_emit", move |a: bool, msg: &str| {
             if a {
-                messages.borrow_mut().push(msg.to_owned());
+                messages.borrow_mut().push(Diagnostic::info(msg.to_owned()));
             }
             a
         });
     }
     {
         let messages = messages.clone();
-        engine.register_fn("then_emit", move |a: bool, m: Map| {
+        engine.register_fn("then_emit", move |a: bool, m: Map| -> ScriptResult<bool> {
             if a {
-                let msg = m
-                    .get("msg")
-                    .map(|e| e.clone().into_string().unwrap())
-                    .unwrap();
-                messages.borrow_mut().push(msg);
+                messages.borrow_mut().push(diagnostic_from_map(&m)?);
             }
-            a
+            Ok(a)
         });
     }
     engine.register_custom_operator("or_emit", 15).unwrap();
@@ -475,23 +588,87 @@ pub fn build_engine(messages: Rc<RefCell<Vec<String>>>, debug: bool) -> Engine {
         let messages = messages.clone();
         engine.register_fn("or_emit", move |a: bool, msg: &str| {
             if !a {
-                messages.borrow_mut().push(msg.to_owned());
+                messages.borrow_mut().push(Diagnostic::info(msg.to_owned()));
             }
             a
         });
     }
     {
-        engine.register_fn("or_emit", move |a: bool, m: Map| {
+        let messages = messages.clone();
+        engine.register_fn("or_emit", move |a: bool, m: Map| -> ScriptResult<bool> {
             if !a {
-                let msg = m
-                    .get("msg")
-                    .map(|e| e.clone().into_string().unwrap())
-                    .unwrap();
-                messages.borrow_mut().push(msg);
+                messages.borrow_mut().push(diagnostic_from_map(&m)?);
             }
-            a
+            Ok(a)
         });
     }
+
+    macro_rules! register_severity_emit {
+        ($(($name: literal, $severity: expr)),*) => {
+            $(
+            {
+                let messages = messages.clone();
+                engine.register_fn($name, move |msg: &str| {
+                    messages
+                        .borrow_mut()
+                        .push(Diagnostic::new($severity, msg.to_owned()));
+                });
+            }
+            )*
+        };
+    }
+
+    register_severity_emit!(
+        ("error", Severity::Error),
+        ("warn", Severity::Warning),
+        ("info", Severity::Info),
+        ("hint", Severity::Hint)
+    );
+
+    // `rule "name" severity warn when <expr> emit "message"` — a named, self-documenting
+    // alternative to a bare `<expr> then_emit "message"` that also records its name in
+    // `rule_names` for future enable/disable-by-name support.
+    {
+        let messages = messages.clone();
+        let rule_names = rule_names.clone();
+        engine
+            .register_custom_syntax(
+                [
+                    "rule", "$string$", "severity", "$ident$", "when", "$expr$", "emit", "$string$",
+                ],
+                false,
+                move |context, inputs| {
+                    let name = inputs[0]
+                        .get_string_value()
+                        .ok_or("rule name must be a string literal")?
+                        .to_owned();
+                    let severity_name = inputs[1]
+                        .get_string_value()
+                        .ok_or("rule severity must be an identifier")?;
+                    let severity = Severity::parse(severity_name)
+                        .ok_or_else(|| format!("unknown severity `{severity_name}`"))?;
+                    let message = inputs[3]
+                        .get_string_value()
+                        .ok_or("rule message must be a string literal")?
+                        .to_owned();
+
+                    rule_names.borrow_mut().insert(name.clone());
+
+                    let condition = inputs[2]
+                        .eval_with_context(context)?
+                        .as_bool()
+                        .map_err(error_to_runtime_error)?;
+                    if condition {
+                        messages
+                            .borrow_mut()
+                            .push(Diagnostic::new(severity, message).with_rule(name));
+                    }
+
+                    Ok(Dynamic::UNIT)
+                },
+            )
+            .unwrap();
+    }
     // END DSL
 
     engine
@@ -500,7 +677,12 @@ pub fn build_engine(messages: Rc<RefCell<Vec<String>>>, debug: bool) -> Engine {
         .register_get("value", Token::value)
         .register_get("quote_style", |t: &mut Token| -> ScriptResult<String> {
             Token::quote_style(t).ok_or("no quote style".into())
-        });
+        })
+        .register_get("line", Token::line)
+        .register_get("col", Token::col)
+        .register_get("span_start", Token::span_start)
+        .register_get("span_end", Token::span_end)
+        .register_get("span", token_span_map);
 
     if debug {
         engine.on_print(move |x| eprintln!("INFO => {x}"));
@@ -512,5 +694,14 @@ pub fn build_engine(messages: Rc<RefCell<Vec<String>>>, debug: bool) -> Engine {
 
     engine.disable_symbol("eval");
 
+    if allow_fs {
+        engine
+            .register_fn("exists", script_exists)
+            .register_fn("is_file", script_is_file)
+            .register_fn("is_dir", script_is_dir)
+            .register_fn("read_file", script_read_file)
+            .register_fn("glob", script_glob);
+    }
+
     engine
 }
@@ -1,54 +1,217 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::iter::Peekable;
-use std::str::Chars;
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use stringlit::s;
 
+#[derive(Debug, Default)]
+struct AtomTable {
+    ids: HashMap<String, u32>,
+    strings: Vec<String>,
+}
+
+impl AtomTable {
+    fn intern(&mut self, s: &str) -> u32 {
+        if let Some(&id) = self.ids.get(s) {
+            return id;
+        }
+        let id = u32::try_from(self.strings.len()).expect("too many interned strings");
+        self.strings.push(s.to_owned());
+        self.ids.insert(s.to_owned(), id);
+        id
+    }
+
+    fn resolve(&self, id: u32) -> &str {
+        &self.strings[id as usize]
+    }
+}
+
+thread_local! {
+    static ATOMS: RefCell<AtomTable> = RefCell::new(AtomTable::default());
+}
+
+/// Interns `s` into the current thread's atom table, returning its id.
+///
+/// Repeated interning of the same string is O(1) after the first call and
+/// always returns the same [`Atom`], so equality between interned tokens
+/// reduces to a `u32` compare.
+#[must_use]
+pub fn intern(s: &str) -> Atom {
+    Atom(ATOMS.with(|table| table.borrow_mut().intern(s)))
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct Atom(u32);
+
+impl Atom {
+    #[must_use]
+    pub fn resolve(self) -> String {
+        ATOMS.with(|table| table.borrow().resolve(self.0).to_owned())
+    }
+}
+
+impl Serialize for Atom {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.resolve())
+    }
+}
+
+impl<'de> Deserialize<'de> for Atom {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Ok(intern(&s))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: u32,
+    pub col: u32,
+}
+
+impl Span {
+    #[must_use]
+    pub fn new(start: usize, end: usize, line: u32, col: u32) -> Self {
+        Self {
+            start,
+            end,
+            line,
+            col,
+        }
+    }
+}
+
+impl Default for Span {
+    fn default() -> Self {
+        Self::new(0, 0, 1, 1)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum QuoteStyle {
     Single,
     Double,
 }
 
+/// A recoverable failure while lexing, carrying the [`Span`] where it occurred.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum LexError {
+    UnterminatedString { quote: char, span: Span },
+}
+
+impl std::fmt::Display for LexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LexError::UnterminatedString { quote, span } => write!(
+                f,
+                "unterminated string literal starting with {quote} at line {}, col {}",
+                span.line, span.col
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LexError {}
+
+/// The normalized value of a numeric literal, used to compare numbers by value rather than
+/// by spelling, so `3.0` matches `3.00` and `0xFF` matches `255`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum NumberValue {
+    Int(i128),
+    Float(f64),
+    /// A value too large for `i128`, or a lexeme that does not parse as a number, kept as
+    /// cleaned (separator-free) text.
+    Big(String),
+}
+
+/// Parses a numeric lexeme, as produced by the tokenizer or written literally in a rules
+/// file, into its normalized [`NumberValue`]. Strips `_` digit separators and honors
+/// `0x`/`0b`/`0o` base prefixes and `e`/`E` exponents.
+#[must_use]
+pub fn parse_number(lexeme: &str) -> NumberValue {
+    let cleaned: String = lexeme.chars().filter(|&c| c != '_').collect();
+
+    for (prefix, radix) in [
+        ("0x", 16),
+        ("0X", 16),
+        ("0b", 2),
+        ("0B", 2),
+        ("0o", 8),
+        ("0O", 8),
+    ] {
+        if let Some(digits) = cleaned.strip_prefix(prefix) {
+            return i128::from_str_radix(digits, radix)
+                .map_or_else(|_| NumberValue::Big(cleaned.clone()), NumberValue::Int);
+        }
+    }
+
+    if cleaned.contains('.') || cleaned.contains('e') || cleaned.contains('E') {
+        return cleaned
+            .parse::<f64>()
+            .map_or_else(|_| NumberValue::Big(cleaned.clone()), NumberValue::Float);
+    }
+
+    cleaned
+        .parse::<i128>()
+        .map_or_else(|_| NumberValue::Big(cleaned.clone()), NumberValue::Int)
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum Token {
-    Identifier(String),
-    Number(String),
-    Symbol(String),
-    OpenParen(String),
-    CloseParen(String),
-    StringLiteral(String, QuoteStyle),
+    Identifier(Atom, #[serde(default)] Span),
+    Number(Atom, #[serde(default)] Span),
+    Symbol(Atom, #[serde(default)] Span),
+    OpenParen(Atom, #[serde(default)] Span),
+    CloseParen(Atom, #[serde(default)] Span),
+    StringLiteral(Atom, QuoteStyle, #[serde(default)] Span),
+    /// An identifier that matched an entry in the [`LexerConfig`] keyword table.
+    Keyword(Atom, #[serde(default)] Span),
 }
 
 impl Token {
     pub fn enum_type(&mut self) -> String {
         match self {
-            Token::Identifier(_) => "Identifier",
-            Token::Number(_) => "Number",
-            Token::Symbol(_) => "Symbol",
-            Token::OpenParen(_) => "OpenParen",
-            Token::CloseParen(_) => "CloseParen",
-            Token::StringLiteral(_, _) => "StringLiteral",
+            Token::Identifier(_, _) => "Identifier",
+            Token::Number(_, _) => "Number",
+            Token::Symbol(_, _) => "Symbol",
+            Token::OpenParen(_, _) => "OpenParen",
+            Token::CloseParen(_, _) => "CloseParen",
+            Token::StringLiteral(_, _, _) => "StringLiteral",
+            Token::Keyword(_, _) => "Keyword",
         }
         .to_owned()
     }
 
+    /// Resolves the token's interned payload back to an owned `String`.
     pub fn value(&mut self) -> String {
         match self {
-            Token::Identifier(s)
-            | Token::Number(s)
-            | Token::Symbol(s)
-            | Token::OpenParen(s)
-            | Token::CloseParen(s)
-            | Token::StringLiteral(s, _) => s,
+            Token::Identifier(a, _)
+            | Token::Number(a, _)
+            | Token::Symbol(a, _)
+            | Token::OpenParen(a, _)
+            | Token::CloseParen(a, _)
+            | Token::StringLiteral(a, _, _)
+            | Token::Keyword(a, _) => a.resolve(),
+        }
+    }
+
+    pub fn atom(&self) -> Atom {
+        match self {
+            Token::Identifier(a, _)
+            | Token::Number(a, _)
+            | Token::Symbol(a, _)
+            | Token::OpenParen(a, _)
+            | Token::CloseParen(a, _)
+            | Token::StringLiteral(a, _, _)
+            | Token::Keyword(a, _) => *a,
         }
-        .to_owned()
     }
 
     pub fn quote_style(&mut self) -> Option<String> {
         match self {
-            Token::StringLiteral(_, s) => Some(
+            Token::StringLiteral(_, s, _) => Some(
                 match s {
                     QuoteStyle::Single => "Single",
                     QuoteStyle::Double => "Double",
@@ -58,6 +221,120 @@ impl Token {
             _ => None,
         }
     }
+
+    pub fn span(&self) -> Span {
+        match self {
+            Token::Identifier(_, span)
+            | Token::Number(_, span)
+            | Token::Symbol(_, span)
+            | Token::OpenParen(_, span)
+            | Token::CloseParen(_, span)
+            | Token::StringLiteral(_, _, span)
+            | Token::Keyword(_, span) => *span,
+        }
+    }
+
+    #[allow(clippy::cast_possible_wrap)]
+    pub fn line(&mut self) -> i64 {
+        i64::from(self.span().line)
+    }
+
+    #[allow(clippy::cast_possible_wrap)]
+    pub fn col(&mut self) -> i64 {
+        i64::from(self.span().col)
+    }
+
+    #[allow(clippy::cast_possible_wrap)]
+    pub fn span_start(&mut self) -> i64 {
+        self.span().start as i64
+    }
+
+    #[allow(clippy::cast_possible_wrap)]
+    pub fn span_end(&mut self) -> i64 {
+        self.span().end as i64
+    }
+}
+
+/// A parenthesized token tree: a [`Leaf`](Node::Leaf) for anything outside parens, or a
+/// [`Group`](Node::Group) for a balanced `(...)` subtree. The original open/close tokens are
+/// kept so [`flatten`] can round-trip the input exactly.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Node {
+    Leaf(Token),
+    Group(Token, Vec<Node>, Token),
+}
+
+/// A failure building a [`Node`] tree from unbalanced parens, carrying the [`Span`] of the
+/// offending paren.
+#[derive(Debug, PartialEq, Clone)]
+pub enum TreeError {
+    UnmatchedOpenParen { span: Span },
+    UnmatchedCloseParen { span: Span },
+}
+
+impl std::fmt::Display for TreeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TreeError::UnmatchedOpenParen { span } => {
+                write!(f, "unmatched '(' at line {}, col {}", span.line, span.col)
+            }
+            TreeError::UnmatchedCloseParen { span } => {
+                write!(f, "unmatched ')' at line {}, col {}", span.line, span.col)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TreeError {}
+
+/// Builds a [`Node`] tree from a flat token stream, pairing each `OpenParen` with its
+/// matching `CloseParen`.
+pub fn build_tree(tokens: Vec<Token>) -> Result<Vec<Node>, TreeError> {
+    let mut iter = tokens.into_iter().peekable();
+    let nodes = build_tree_inner(&mut iter)?;
+    if let Some(extra) = iter.next() {
+        return Err(TreeError::UnmatchedCloseParen { span: extra.span() });
+    }
+    Ok(nodes)
+}
+
+fn build_tree_inner(
+    iter: &mut std::iter::Peekable<std::vec::IntoIter<Token>>,
+) -> Result<Vec<Node>, TreeError> {
+    let mut nodes = Vec::new();
+    while !matches!(iter.peek(), Some(Token::CloseParen(_, _)) | None) {
+        let token = iter.next().unwrap();
+        if matches!(token, Token::OpenParen(_, _)) {
+            let open_span = token.span();
+            let children = build_tree_inner(iter)?;
+            match iter.next() {
+                Some(close @ Token::CloseParen(_, _)) => {
+                    nodes.push(Node::Group(token, children, close));
+                }
+                _ => return Err(TreeError::UnmatchedOpenParen { span: open_span }),
+            }
+        } else {
+            nodes.push(Node::Leaf(token));
+        }
+    }
+    Ok(nodes)
+}
+
+/// Flattens a [`Node`] tree back into a flat token stream, the inverse of [`build_tree`].
+#[must_use]
+pub fn flatten(nodes: Vec<Node>) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for node in nodes {
+        match node {
+            Node::Leaf(token) => tokens.push(token),
+            Node::Group(open, children, close) => {
+                tokens.push(open);
+                tokens.extend(flatten(children));
+                tokens.push(close);
+            }
+        }
+    }
+    tokens
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -67,111 +344,347 @@ pub enum QuoteStylePattern {
     Any,
 }
 
+/// Lexer behavior an embedder can opt into: comment markers to skip and identifiers that
+/// should lex as [`Token::Keyword`] instead of [`Token::Identifier`].
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct LexerConfig {
+    /// Markers that start a comment running to end of line, e.g. `"#"` or `"//"`.
+    #[serde(default)]
+    pub line_comments: Vec<String>,
+    /// `(open, close)` delimiter pairs for block comments, e.g. `("/*", "*/")`.
+    #[serde(default)]
+    pub block_comments: Vec<(String, String)>,
+    /// Identifiers that lex as [`Token::Keyword`] rather than [`Token::Identifier`].
+    #[serde(default)]
+    pub keywords: std::collections::HashSet<String>,
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum Pattern {
-    Identifier(String),                   // Matches a specific identifier
-    Number(String),                       // Matches a specific number
+    Identifier(Atom),                     // Matches a specific identifier
+    Number(Atom),                         // Matches a specific number
     AnyIdentifier(String),                // Matches any identifier and binds it
     AnyNumber(String),                    // Matches any number and binds it
-    Symbol(String),                       // Matches a specific symbol
+    Symbol(Atom),                         // Matches a specific symbol
     OpenParen(String),                    // Matches an open parenthesis
     CloseParen(String),                   // Matches a close parenthesis
     String(String, QuoteStylePattern),    // Matches a specific string
     AnyString(String, QuoteStylePattern), // Matches any string and binds it
     Any,                                  // Matches any single token
+    Group(Vec<Pattern>),                  // Matches a balanced (...) whose contents match
+    AnyGroup(String),                     // Matches any balanced (...) and binds the whole subtree
+    Repeat(Box<Pattern>, String),         // Greedily matches zero-or-more tokens and binds the run
+    Keyword(Atom),                        // Matches a specific keyword
+}
+
+/// The interned `"*"` wildcard, shared by [`Pattern::Identifier`], [`Pattern::Number`] and
+/// [`Pattern::Symbol`] literals to mean "match any token of this kind".
+fn wildcard_atom() -> Atom {
+    intern("*")
 }
 
+/// Compares two numeric atoms by their normalized [`NumberValue`] rather than by spelling,
+/// so a rule written as `3.0` matches a token spelled `3.00`.
+fn numbers_equal(a: Atom, b: Atom) -> bool {
+    parse_number(&a.resolve()) == parse_number(&b.resolve())
+}
+
+/// A lexer over raw bytes rather than `Peekable<Chars>`: every structural token (parens,
+/// digits, ASCII operators, whitespace) is single-byte and can be dispatched without paying
+/// UTF-8 decode cost; a full scalar is decoded only at a non-ASCII byte (in an identifier,
+/// string body, symbol or comment, or non-ASCII whitespace).
 pub struct Tokenizer<'a> {
-    input: Peekable<Chars<'a>>,
+    input: &'a [u8],
+    pos: usize,
+    line: u32,
+    col: u32,
+    config: LexerConfig,
 }
 
 impl<'a> Tokenizer<'a> {
     #[must_use]
     pub fn new(input: &'a str) -> Self {
+        Self::with_config(input, LexerConfig::default())
+    }
+
+    #[must_use]
+    pub fn with_config(input: &'a str, config: LexerConfig) -> Self {
         Self {
-            input: input.chars().peekable(),
+            input: input.as_bytes(),
+            pos: 0,
+            line: 1,
+            col: 1,
+            config,
         }
     }
 
-    pub fn next_token(&mut self) -> Option<Token> {
-        while let Some(&ch) = self.input.peek() {
-            match ch {
-                ' ' | '\t' | '\n' | '\r' => {
-                    self.input.next(); // Skip whitespace
+    /// Whether the upcoming input starts with the literal string `s`, without consuming it.
+    fn peek_str(&self, s: &str) -> bool {
+        self.input[self.pos..].starts_with(s.as_bytes())
+    }
+
+    /// Skips a comment at the current position, if `self.config` declares one starting here.
+    /// Returns whether a comment (and therefore at least one character) was skipped.
+    fn skip_comment(&mut self) -> bool {
+        if let Some(marker) = self
+            .config
+            .line_comments
+            .iter()
+            .find(|marker| self.peek_str(marker))
+            .cloned()
+        {
+            for _ in 0..marker.chars().count() {
+                self.advance();
+            }
+            while let Some(ch) = self.peek_char() {
+                if ch == '\n' {
+                    break;
                 }
-                '(' => {
-                    self.input.next();
-                    return Some(Token::OpenParen(s!("(")));
+                self.advance();
+            }
+            return true;
+        }
+
+        if let Some((open, close)) = self
+            .config
+            .block_comments
+            .iter()
+            .find(|(open, _)| self.peek_str(open))
+            .cloned()
+        {
+            for _ in 0..open.chars().count() {
+                self.advance();
+            }
+            while self.peek_char().is_some() && !self.peek_str(&close) {
+                self.advance();
+            }
+            for _ in 0..close.chars().count() {
+                self.advance();
+            }
+            return true;
+        }
+
+        false
+    }
+
+    /// Decodes the UTF-8 scalar at byte offset `pos`, taking the single-byte ASCII fast path
+    /// without any validation when possible, and returns it alongside its length in bytes.
+    fn char_at(&self, pos: usize) -> Option<(char, usize)> {
+        let b0 = *self.input.get(pos)?;
+        if b0 < 0x80 {
+            return Some((b0 as char, 1));
+        }
+        let len = if b0 & 0xE0 == 0xC0 {
+            2
+        } else if b0 & 0xF0 == 0xE0 {
+            3
+        } else {
+            4
+        };
+        let bytes = self.input.get(pos..pos + len)?;
+        let ch = std::str::from_utf8(bytes).ok()?.chars().next()?;
+        Some((ch, len))
+    }
+
+    fn peek_char(&self) -> Option<char> {
+        self.char_at(self.pos).map(|(ch, _)| ch)
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let (ch, len) = self.char_at(self.pos)?;
+        self.pos += len;
+        if ch == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(ch)
+    }
+
+    fn span_from(&self, start: usize, line: u32, col: u32) -> Span {
+        Span::new(start, self.pos, line, col)
+    }
+
+    pub fn next_token(&mut self) -> Result<Option<Token>, LexError> {
+        while let Some(&b) = self.input.get(self.pos) {
+            if self.skip_comment() {
+                continue;
+            }
+            match b {
+                b' ' | b'\t' | b'\n' | b'\r' => {
+                    self.advance(); // Skip whitespace
+                }
+                b'(' => {
+                    let (start, line, col) = (self.pos, self.line, self.col);
+                    self.advance();
+                    return Ok(Some(Token::OpenParen(
+                        intern("("),
+                        self.span_from(start, line, col),
+                    )));
                 }
-                ')' => {
-                    self.input.next();
-                    return Some(Token::CloseParen(s!(")")));
+                b')' => {
+                    let (start, line, col) = (self.pos, self.line, self.col);
+                    self.advance();
+                    return Ok(Some(Token::CloseParen(
+                        intern(")"),
+                        self.span_from(start, line, col),
+                    )));
                 }
-                '0'..='9' => {
-                    return Some(self.consume_number());
+                b'0'..=b'9' => {
+                    return Ok(Some(self.consume_number()));
                 }
-                'a'..='z' | 'A'..='Z' | '_' => {
-                    return Some(self.consume_identifier());
+                b'a'..=b'z' | b'A'..=b'Z' | b'_' => {
+                    return Ok(Some(self.consume_identifier()));
                 }
-                '\'' => {
-                    return Some(self.consume_string(QuoteStyle::Single));
+                b'\'' => {
+                    return self.consume_string(QuoteStyle::Single).map(Some);
                 }
-                '"' => {
-                    return Some(self.consume_string(QuoteStyle::Double));
+                b'"' => {
+                    return self.consume_string(QuoteStyle::Double).map(Some);
                 }
                 _ => {
-                    return Some(self.consume_symbol());
+                    // Non-ASCII identifier starts (e.g. `λ`, CJK characters) never match the
+                    // ASCII-only arm above, but `consume_symbol` breaks on the first
+                    // alphabetic scalar without consuming it, which would spin forever here.
+                    if self.peek_char().is_some_and(char::is_alphabetic) {
+                        return Ok(Some(self.consume_identifier()));
+                    }
+                    // Same problem for non-ASCII whitespace (NBSP, line/paragraph separators,
+                    // etc.): the ASCII-only whitespace arm above misses it, and
+                    // `consume_symbol` would break on it without consuming a byte.
+                    if self.peek_char().is_some_and(char::is_whitespace) {
+                        self.advance();
+                        continue;
+                    }
+                    return Ok(Some(self.consume_symbol()));
                 }
             }
         }
-        None
+        Ok(None)
     }
 
+    /// Skips to the next whitespace (or end of input) so tokenizing can resume after a
+    /// [`LexError`].
+    pub fn skip_to_boundary(&mut self) {
+        while let Some(ch) = self.peek_char() {
+            if ch.is_whitespace() {
+                break;
+            }
+            self.advance();
+        }
+    }
+
+    /// Consumes a numeric literal: an optional `0x`/`0b`/`0o` base prefix, otherwise a
+    /// decimal digit run with an optional fractional part and `e`/`E` exponent. `_` digit
+    /// separators are accepted anywhere digits are expected. The original spelling is kept
+    /// verbatim in the returned token so the formatter can round-trip it unchanged.
     fn consume_number(&mut self) -> Token {
+        let (start, line, col) = (self.pos, self.line, self.col);
         let mut number = String::new();
-        while let Some(&ch) = self.input.peek() {
-            if ch.is_numeric() {
+
+        if self.peek_char() == Some('0') {
+            number.push(self.advance().unwrap());
+            if matches!(self.peek_char(), Some('x' | 'X' | 'b' | 'B' | 'o' | 'O')) {
+                number.push(self.advance().unwrap());
+                while let Some(ch) = self.peek_char() {
+                    if ch.is_ascii_alphanumeric() || ch == '_' {
+                        number.push(ch);
+                        self.advance();
+                    } else {
+                        break;
+                    }
+                }
+                return Token::Number(intern(&number), self.span_from(start, line, col));
+            }
+        }
+
+        while let Some(ch) = self.peek_char() {
+            if ch.is_numeric() || ch == '_' {
                 number.push(ch);
-                self.input.next();
+                self.advance();
             } else {
                 break;
             }
         }
-        Token::Number(number)
+
+        if self.peek_char() == Some('.') {
+            number.push(self.advance().unwrap());
+            while let Some(ch) = self.peek_char() {
+                if ch.is_numeric() || ch == '_' {
+                    number.push(ch);
+                    self.advance();
+                } else {
+                    break;
+                }
+            }
+        }
+
+        if matches!(self.peek_char(), Some('e' | 'E')) {
+            number.push(self.advance().unwrap());
+            if matches!(self.peek_char(), Some('+' | '-')) {
+                number.push(self.advance().unwrap());
+            }
+            while let Some(ch) = self.peek_char() {
+                if ch.is_numeric() || ch == '_' {
+                    number.push(ch);
+                    self.advance();
+                } else {
+                    break;
+                }
+            }
+        }
+
+        Token::Number(intern(&number), self.span_from(start, line, col))
     }
 
     fn consume_identifier(&mut self) -> Token {
+        let (start, line, col) = (self.pos, self.line, self.col);
         let mut identifier = String::new();
-        while let Some(&ch) = self.input.peek() {
+        while let Some(ch) = self.peek_char() {
             if ch.is_alphanumeric() || ch == '_' {
                 identifier.push(ch);
-                self.input.next();
+                self.advance();
             } else {
                 break;
             }
         }
-        Token::Identifier(identifier)
+        let span = self.span_from(start, line, col);
+        if self.config.keywords.contains(&identifier) {
+            Token::Keyword(intern(&identifier), span)
+        } else {
+            Token::Identifier(intern(&identifier), span)
+        }
     }
 
-    fn consume_string(&mut self, quote_style: QuoteStyle) -> Token {
-        let quote = self.input.next().unwrap(); // Consume the opening quote
+    fn consume_string(&mut self, quote_style: QuoteStyle) -> Result<Token, LexError> {
+        let (start, line, col) = (self.pos, self.line, self.col);
+        let quote = self.advance().unwrap(); // Consume the opening quote
         let mut string_content = String::new();
 
-        while let Some(&ch) = self.input.peek() {
+        while let Some(ch) = self.peek_char() {
             if ch == quote {
-                self.input.next(); // Consume the closing quote
-                return Token::StringLiteral(string_content, quote_style);
+                self.advance(); // Consume the closing quote
+                return Ok(Token::StringLiteral(
+                    intern(&string_content),
+                    quote_style,
+                    self.span_from(start, line, col),
+                ));
             }
             string_content.push(ch);
-            self.input.next();
+            self.advance();
         }
 
-        panic!("Unterminated string literal");
+        Err(LexError::UnterminatedString {
+            quote,
+            span: self.span_from(start, line, col),
+        })
     }
 
     fn consume_symbol(&mut self) -> Token {
+        let (start, line, col) = (self.pos, self.line, self.col);
         let mut symbol = String::new();
-        while let Some(&ch) = self.input.peek() {
+        while let Some(ch) = self.peek_char() {
             if ch.is_alphanumeric()
                 || ch.is_whitespace()
                 || ch == '('
@@ -182,12 +695,145 @@ impl<'a> Tokenizer<'a> {
                 break;
             }
             symbol.push(ch);
-            self.input.next();
+            self.advance();
         }
-        Token::Symbol(symbol)
+        Token::Symbol(intern(&symbol), self.span_from(start, line, col))
     }
 }
 
+/// A value captured by a [`Pattern`] when matching a flat token stream: either a single
+/// token, or the ordered run a [`Pattern::Repeat`] consumed.
+#[derive(Debug, PartialEq, Clone)]
+enum Binding {
+    Token(Token),
+    Many(Vec<Token>),
+}
+
+/// Whether `pattern` matches a single leaf `token`. Does not handle [`Pattern::Repeat`],
+/// [`Pattern::Group`] or [`Pattern::AnyGroup`], which only make sense against a run of
+/// tokens or a [`Node`] tree.
+fn single_pattern_matches(pattern: &Pattern, token: &Token) -> bool {
+    let wildcard = wildcard_atom();
+    match (pattern, token) {
+        (Pattern::Identifier(p), Token::Identifier(t, _))
+        | (Pattern::Symbol(p), Token::Symbol(t, _))
+        | (Pattern::Keyword(p), Token::Keyword(t, _))
+            if p == t || *p == wildcard =>
+        {
+            true
+        }
+        (Pattern::Number(p), Token::Number(t, _)) if *p == wildcard || numbers_equal(*p, *t) => {
+            true
+        }
+        (Pattern::OpenParen(p), Token::OpenParen(t, _))
+        | (Pattern::CloseParen(p), Token::CloseParen(t, _))
+        | (
+            Pattern::String(p, QuoteStylePattern::Double),
+            Token::StringLiteral(t, QuoteStyle::Double, _),
+        )
+        | (
+            Pattern::String(p, QuoteStylePattern::Single),
+            Token::StringLiteral(t, QuoteStyle::Single, _),
+        )
+        | (Pattern::String(p, QuoteStylePattern::Any), Token::StringLiteral(t, _, _))
+            if *p == t.resolve() || p == "*" =>
+        {
+            true
+        }
+        (Pattern::AnyIdentifier(_), Token::Identifier(_, _))
+        | (Pattern::AnyNumber(_), Token::Number(_, _))
+        | (
+            Pattern::AnyString(_, QuoteStylePattern::Double),
+            Token::StringLiteral(_, QuoteStyle::Double, _),
+        )
+        | (
+            Pattern::AnyString(_, QuoteStylePattern::Single),
+            Token::StringLiteral(_, QuoteStyle::Single, _),
+        )
+        | (Pattern::AnyString(_, QuoteStylePattern::Any), Token::StringLiteral(_, _, _))
+        | (Pattern::Any, _) => true,
+        _ => false,
+    }
+}
+
+/// Records the binding, if any, that matching `pattern` against a single leaf `token`
+/// produces.
+fn bind_single(pattern: &Pattern, token: &Token, bindings: &mut HashMap<String, Binding>) {
+    match (pattern, token) {
+        (Pattern::AnyIdentifier(name), Token::Identifier(t, span)) => {
+            bindings.insert(name.clone(), Binding::Token(Token::Identifier(*t, *span)));
+        }
+        (Pattern::AnyNumber(name), Token::Number(t, span)) => {
+            bindings.insert(name.clone(), Binding::Token(Token::Number(*t, *span)));
+        }
+        (
+            Pattern::AnyString(name, QuoteStylePattern::Double),
+            Token::StringLiteral(t, QuoteStyle::Double, span),
+        ) => {
+            bindings.insert(
+                name.clone(),
+                Binding::Token(Token::StringLiteral(*t, QuoteStyle::Double, *span)),
+            );
+        }
+        (
+            Pattern::AnyString(name, QuoteStylePattern::Single),
+            Token::StringLiteral(t, QuoteStyle::Single, span),
+        ) => {
+            bindings.insert(
+                name.clone(),
+                Binding::Token(Token::StringLiteral(*t, QuoteStyle::Single, *span)),
+            );
+        }
+        (Pattern::AnyString(name, QuoteStylePattern::Any), Token::StringLiteral(t, sl, span)) => {
+            bindings.insert(
+                name.clone(),
+                Binding::Token(Token::StringLiteral(*t, sl.clone(), *span)),
+            );
+        }
+        (Pattern::Any, t) => {
+            bindings.insert(s!("_"), Binding::Token(t.clone()));
+        }
+        _ => {}
+    }
+}
+
+/// Matches `patterns` against a prefix of `tokens`, returning the captured bindings and the
+/// number of tokens consumed. A [`Pattern::Repeat`] first greedily consumes every token
+/// satisfying its inner pattern, then backtracks one token at a time until the remaining
+/// patterns also match, so a fixed pattern following a `Repeat` can still find its match.
+fn match_patterns(
+    patterns: &[Pattern],
+    tokens: &[Token],
+) -> Option<(HashMap<String, Binding>, usize)> {
+    let Some((first, rest_patterns)) = patterns.split_first() else {
+        return Some((HashMap::new(), 0));
+    };
+
+    if let Pattern::Repeat(inner, name) = first {
+        let mut max_run = 0;
+        while max_run < tokens.len() && single_pattern_matches(inner, &tokens[max_run]) {
+            max_run += 1;
+        }
+        for run_len in (0..=max_run).rev() {
+            if let Some((mut bindings, rest_len)) =
+                match_patterns(rest_patterns, &tokens[run_len..])
+            {
+                bindings.insert(name.clone(), Binding::Many(tokens[..run_len].to_vec()));
+                return Some((bindings, run_len + rest_len));
+            }
+        }
+        return None;
+    }
+
+    let token = tokens.first()?;
+    if !single_pattern_matches(first, token) {
+        return None;
+    }
+    let (mut bindings, rest_len) = match_patterns(rest_patterns, &tokens[1..])?;
+    bind_single(first, token, &mut bindings);
+    Some((bindings, 1 + rest_len))
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Rule {
     pattern: Vec<Pattern>,
@@ -203,125 +849,223 @@ impl Rule {
         }
     }
 
-    fn will_match(&self, tokens: &[Token]) -> bool {
-        for (pattern, token) in self.pattern.iter().zip(tokens) {
-            match (pattern, token) {
-                (Pattern::Identifier(p), Token::Identifier(t))
-                | (Pattern::Number(p), Token::Number(t))
-                | (Pattern::Symbol(p), Token::Symbol(t))
-                | (Pattern::OpenParen(p), Token::OpenParen(t))
-                | (Pattern::CloseParen(p), Token::CloseParen(t))
-                | (
-                    Pattern::String(p, QuoteStylePattern::Double),
-                    Token::StringLiteral(t, QuoteStyle::Double),
-                )
-                | (
-                    Pattern::String(p, QuoteStylePattern::Single),
-                    Token::StringLiteral(t, QuoteStyle::Single),
-                )
-                | (Pattern::String(p, QuoteStylePattern::Any), Token::StringLiteral(t, _))
-                    if p == t || p == "*" => {}
-                (Pattern::AnyIdentifier(_), Token::Identifier(_))
-                | (Pattern::AnyNumber(_), Token::Number(_))
-                | (
-                    Pattern::AnyString(_, QuoteStylePattern::Double),
-                    Token::StringLiteral(_, QuoteStyle::Double),
-                )
-                | (
-                    Pattern::AnyString(_, QuoteStylePattern::Single),
-                    Token::StringLiteral(_, QuoteStyle::Single),
-                )
-                | (Pattern::AnyString(_, QuoteStylePattern::Any), Token::StringLiteral(_, _))
-                | (Pattern::Any, _) => {}
-                _ => return false,
+    /// Matches `self.pattern` at every start position in `tokens`, returning the captured
+    /// bindings, the start position and the number of tokens consumed for each position that
+    /// matches. The consumed count can differ from
+    /// `self.pattern.len()` when the pattern contains a [`Pattern::Repeat`].
+    fn matches(&self, tokens: &[Token]) -> Vec<(HashMap<String, Binding>, usize, usize)> {
+        let mut result = Vec::new();
+        for i in 0..=tokens.len() {
+            if let Some((bindings, matched_len)) = match_patterns(&self.pattern, &tokens[i..]) {
+                result.push((bindings, i, matched_len));
+            }
+        }
+        result
+    }
+
+    fn apply(&self, bindings: &HashMap<String, Binding>) -> Vec<Token> {
+        self.replacement
+            .iter()
+            .flat_map(|token| {
+                let name = match token {
+                    Token::Identifier(id, _) => Some(id.resolve()),
+                    Token::Number(num, _) => Some(num.resolve()),
+                    Token::Symbol(sym, _) => Some(sym.resolve()),
+                    Token::StringLiteral(str, _, _) => Some(str.resolve()),
+                    _ => None,
+                };
+                match name.and_then(|name| bindings.get(&name)) {
+                    Some(Binding::Many(tokens)) => tokens.clone(),
+                    Some(Binding::Token(t)) => vec![t.clone()],
+                    None => vec![token.clone()],
+                }
+            })
+            .collect()
+    }
+}
+
+/// A value captured by a [`Pattern`] when matching a [`Node`] tree: either a single leaf
+/// token, as in the flat matcher, or a whole captured subtree from [`Pattern::AnyGroup`].
+#[derive(Debug, PartialEq, Clone)]
+pub enum NodeBinding {
+    Token(Token),
+    Nodes(Vec<Node>),
+}
+
+/// Whether `pattern` matches a single leaf `token`, independent of any `Node` tree structure.
+/// Used by [`node_matches_pattern`] for [`Node::Leaf`] nodes.
+fn leaf_pattern_matches(pattern: &Pattern, token: &Token) -> bool {
+    let wildcard = wildcard_atom();
+    match (pattern, token) {
+        (Pattern::Identifier(p), Token::Identifier(t, _))
+        | (Pattern::Symbol(p), Token::Symbol(t, _))
+        | (Pattern::Keyword(p), Token::Keyword(t, _))
+            if p == t || *p == wildcard =>
+        {
+            true
+        }
+        (Pattern::Number(p), Token::Number(t, _)) if *p == wildcard || numbers_equal(*p, *t) => {
+            true
+        }
+        (Pattern::OpenParen(p), Token::OpenParen(t, _))
+        | (Pattern::CloseParen(p), Token::CloseParen(t, _))
+        | (
+            Pattern::String(p, QuoteStylePattern::Double),
+            Token::StringLiteral(t, QuoteStyle::Double, _),
+        )
+        | (
+            Pattern::String(p, QuoteStylePattern::Single),
+            Token::StringLiteral(t, QuoteStyle::Single, _),
+        )
+        | (Pattern::String(p, QuoteStylePattern::Any), Token::StringLiteral(t, _, _))
+            if *p == t.resolve() || p == "*" =>
+        {
+            true
+        }
+        (Pattern::AnyIdentifier(_), Token::Identifier(_, _))
+        | (Pattern::AnyNumber(_), Token::Number(_, _))
+        | (
+            Pattern::AnyString(_, QuoteStylePattern::Double),
+            Token::StringLiteral(_, QuoteStyle::Double, _),
+        )
+        | (
+            Pattern::AnyString(_, QuoteStylePattern::Single),
+            Token::StringLiteral(_, QuoteStyle::Single, _),
+        )
+        | (Pattern::AnyString(_, QuoteStylePattern::Any), Token::StringLiteral(_, _, _))
+        | (Pattern::Any, _) => true,
+        _ => false,
+    }
+}
+
+/// Records the binding, if any, that matching `pattern` against leaf `token` produces.
+/// Used by [`node_bind`] for [`Node::Leaf`] nodes.
+fn leaf_binding(pattern: &Pattern, token: &Token, bindings: &mut HashMap<String, NodeBinding>) {
+    match (pattern, token) {
+        (Pattern::AnyIdentifier(name), Token::Identifier(t, span)) => {
+            bindings.insert(
+                name.clone(),
+                NodeBinding::Token(Token::Identifier(*t, *span)),
+            );
+        }
+        (Pattern::AnyNumber(name), Token::Number(t, span)) => {
+            bindings.insert(name.clone(), NodeBinding::Token(Token::Number(*t, *span)));
+        }
+        (
+            Pattern::AnyString(name, QuoteStylePattern::Double),
+            Token::StringLiteral(t, QuoteStyle::Double, span),
+        ) => {
+            bindings.insert(
+                name.clone(),
+                NodeBinding::Token(Token::StringLiteral(*t, QuoteStyle::Double, *span)),
+            );
+        }
+        (
+            Pattern::AnyString(name, QuoteStylePattern::Single),
+            Token::StringLiteral(t, QuoteStyle::Single, span),
+        ) => {
+            bindings.insert(
+                name.clone(),
+                NodeBinding::Token(Token::StringLiteral(*t, QuoteStyle::Single, *span)),
+            );
+        }
+        (Pattern::AnyString(name, QuoteStylePattern::Any), Token::StringLiteral(t, sl, span)) => {
+            bindings.insert(
+                name.clone(),
+                NodeBinding::Token(Token::StringLiteral(*t, sl.clone(), *span)),
+            );
+        }
+        (Pattern::Any, t) => {
+            bindings.insert(s!("_"), NodeBinding::Token(t.clone()));
+        }
+        _ => {}
+    }
+}
+
+/// Whether `pattern` matches `node`, recursing into [`Pattern::Group`] against [`Node::Group`]
+/// children.
+fn node_matches_pattern(pattern: &Pattern, node: &Node) -> bool {
+    match node {
+        Node::Leaf(token) => leaf_pattern_matches(pattern, token),
+        Node::Group(_, children, _) => match pattern {
+            Pattern::Any | Pattern::AnyGroup(_) => true,
+            Pattern::Group(sub) => {
+                sub.len() == children.len()
+                    && sub
+                        .iter()
+                        .zip(children)
+                        .all(|(p, c)| node_matches_pattern(p, c))
+            }
+            _ => false,
+        },
+    }
+}
+
+/// Records the bindings produced by matching `pattern` against `node`, recursing into
+/// [`Pattern::Group`] against [`Node::Group`] children.
+fn node_bind(pattern: &Pattern, node: &Node, bindings: &mut HashMap<String, NodeBinding>) {
+    match (pattern, node) {
+        (Pattern::AnyGroup(name), Node::Group(_, _, _)) => {
+            bindings.insert(name.clone(), NodeBinding::Nodes(vec![node.clone()]));
+        }
+        (Pattern::Any, Node::Group(_, _, _)) => {
+            bindings.insert(s!("_"), NodeBinding::Nodes(vec![node.clone()]));
+        }
+        (Pattern::Group(sub), Node::Group(_, children, _)) => {
+            for (p, c) in sub.iter().zip(children) {
+                node_bind(p, c, bindings);
             }
         }
+        (_, Node::Leaf(token)) => leaf_binding(pattern, token, bindings),
+        _ => {}
+    }
+}
 
-        true
+impl Rule {
+    fn node_will_match(&self, nodes: &[Node]) -> bool {
+        self.pattern
+            .iter()
+            .zip(nodes)
+            .all(|(pattern, node)| node_matches_pattern(pattern, node))
     }
 
-    fn matches(&self, tokens: &[Token]) -> Vec<(HashMap<String, Token>, usize)> {
+    fn node_matches(&self, nodes: &[Node]) -> Vec<(HashMap<String, NodeBinding>, usize)> {
         let mut result = Vec::new();
 
-        if tokens.len() < self.pattern.len() {
+        if nodes.len() < self.pattern.len() {
             return result;
         }
 
-        let diff = tokens.len() - self.pattern.len() + 1;
+        let diff = nodes.len() - self.pattern.len() + 1;
         for i in 0..=diff {
-            if !self.will_match(&tokens[i..]) {
+            if !self.node_will_match(&nodes[i..]) {
                 continue;
             }
             let mut bindings = HashMap::new();
-            for (pattern, token) in self.pattern.iter().zip(tokens[i..].iter()) {
-                match (pattern, token) {
-                    (Pattern::Identifier(p), Token::Identifier(t)) if p == t => {}
-                    (Pattern::Number(p), Token::Number(t)) if p == t => {}
-                    (Pattern::AnyIdentifier(name), Token::Identifier(t)) => {
-                        bindings.insert(name.clone(), Token::Identifier(t.clone()));
-                    }
-                    (Pattern::AnyNumber(name), Token::Number(t)) => {
-                        bindings.insert(name.clone(), Token::Number(t.clone()));
-                    }
-                    (Pattern::Symbol(p), Token::Symbol(t)) if p == t => {}
-                    (Pattern::OpenParen(pp), Token::OpenParen(pt)) if pp == pt || pp == "*" => {}
-                    (Pattern::CloseParen(pp), Token::CloseParen(pt)) if pp == pt || pp == "*" => {}
-                    (
-                        Pattern::String(ps, QuoteStylePattern::Double),
-                        Token::StringLiteral(ts, QuoteStyle::Double),
-                    ) if ps == ts => {}
-                    (
-                        Pattern::String(ps, QuoteStylePattern::Single),
-                        Token::StringLiteral(ts, QuoteStyle::Single),
-                    ) if ps == ts => {}
-                    (Pattern::String(ps, QuoteStylePattern::Any), Token::StringLiteral(ts, _))
-                        if ps == ts => {}
-                    (
-                        Pattern::AnyString(name, QuoteStylePattern::Double),
-                        Token::StringLiteral(t, QuoteStyle::Double),
-                    ) => {
-                        bindings.insert(
-                            name.clone(),
-                            Token::StringLiteral(t.clone(), QuoteStyle::Double),
-                        );
-                    }
-                    (
-                        Pattern::AnyString(name, QuoteStylePattern::Single),
-                        Token::StringLiteral(t, QuoteStyle::Single),
-                    ) => {
-                        bindings.insert(
-                            name.clone(),
-                            Token::StringLiteral(t.clone(), QuoteStyle::Single),
-                        );
-                    }
-                    (
-                        Pattern::AnyString(name, QuoteStylePattern::Any),
-                        Token::StringLiteral(t, sl),
-                    ) => {
-                        bindings.insert(name.clone(), Token::StringLiteral(t.clone(), sl.clone()));
-                    }
-                    (Pattern::Any, t) => {
-                        bindings.insert(s!("_"), t.clone());
-                    }
-                    _ => {}
-                }
+            for (pattern, node) in self.pattern.iter().zip(nodes[i..].iter()) {
+                node_bind(pattern, node, &mut bindings);
             }
             result.push((bindings, i));
         }
         result
     }
 
-    fn apply(&self, bindings: &HashMap<String, Token>) -> Vec<Token> {
+    fn node_apply(&self, bindings: &HashMap<String, NodeBinding>) -> Vec<Node> {
         self.replacement
             .iter()
-            .map(|token| match token {
-                Token::Identifier(id) => bindings.get(id).cloned().unwrap_or_else(|| token.clone()),
-                Token::Number(num) => bindings.get(num).cloned().unwrap_or_else(|| token.clone()),
-                Token::Symbol(sym) => bindings.get(sym).cloned().unwrap_or_else(|| token.clone()),
-                Token::StringLiteral(str, _) => {
-                    bindings.get(str).cloned().unwrap_or_else(|| token.clone())
+            .flat_map(|token| {
+                let name = match token {
+                    Token::Identifier(id, _) => Some(id.resolve()),
+                    Token::Number(num, _) => Some(num.resolve()),
+                    Token::Symbol(sym, _) => Some(sym.resolve()),
+                    Token::StringLiteral(str, _, _) => Some(str.resolve()),
+                    _ => None,
+                };
+                match name.and_then(|name| bindings.get(&name)) {
+                    Some(NodeBinding::Nodes(nodes)) => nodes.clone(),
+                    Some(NodeBinding::Token(t)) => vec![Node::Leaf(t.clone())],
+                    None => vec![Node::Leaf(token.clone())],
                 }
-                _ => token.clone(),
             })
             .collect()
     }
@@ -337,22 +1081,31 @@ impl Rewriter {
         Self { rules }
     }
 
+    /// Rewrites `tokens` by applying each rule's matches in turn. A rule's matched length
+    /// (`matched_len`) is no longer always `rule.pattern.len()`: a [`Pattern::Repeat`] can
+    /// consume a different number of tokens per match, so `offset` must be adjusted by the
+    /// actual `replacement.len() - matched_len` delta rather than a fixed pattern length.
     #[must_use]
-    #[allow(clippy::missing_panics_doc)]
     pub fn rewrite(&self, mut tokens: Vec<Token>) -> Vec<Token> {
         for rule in &self.rules {
             let mut offset: i128 = 0;
-            for (bindings, match_pos) in rule.matches(&tokens) {
-                // Adjust the position based on the current offset
-                let adjusted_pos = usize::try_from(match_pos as i128 + offset).unwrap();
+            for (bindings, match_pos, matched_len) in rule.matches(&tokens) {
+                // Adjust the position based on the current offset. A prior splice in this
+                // same rule's pass can shift or invalidate a later reported `match_pos`
+                // (e.g. a variable-length `Pattern::Repeat` match overlapping an earlier
+                // one), so treat an out-of-range adjustment as a stale match to skip rather
+                // than unwrapping.
+                let Ok(adjusted_pos) = usize::try_from(match_pos as i128 + offset) else {
+                    continue;
+                };
 
                 // Ensure the position is valid
-                if adjusted_pos + rule.pattern.len() > tokens.len() {
+                if adjusted_pos + matched_len > tokens.len() {
                     continue;
                 }
 
                 // Remove the matched tokens
-                tokens.drain(adjusted_pos..adjusted_pos + rule.pattern.len());
+                tokens.drain(adjusted_pos..adjusted_pos + matched_len);
 
                 // Insert the replacement tokens
                 let replacement = rule.apply(&bindings);
@@ -361,9 +1114,48 @@ impl Rewriter {
                 }
 
                 // Update the offset based on the size difference
-                offset += replacement.len() as i128 - rule.pattern.len() as i128;
+                offset += replacement.len() as i128 - matched_len as i128;
             }
         }
         tokens
     }
+
+    /// Rewrites a [`Node`] tree the same way [`rewrite`](Self::rewrite) rewrites a flat token
+    /// stream, recursing into each [`Node::Group`]'s children first so rules fire at every
+    /// depth.
+    #[must_use]
+    pub fn rewrite_tree(&self, nodes: Vec<Node>) -> Vec<Node> {
+        let mut nodes: Vec<Node> = nodes
+            .into_iter()
+            .map(|node| match node {
+                Node::Leaf(_) => node,
+                Node::Group(open, children, close) => {
+                    Node::Group(open, self.rewrite_tree(children), close)
+                }
+            })
+            .collect();
+
+        for rule in &self.rules {
+            let mut offset: i128 = 0;
+            for (bindings, match_pos) in rule.node_matches(&nodes) {
+                let Ok(adjusted_pos) = usize::try_from(match_pos as i128 + offset) else {
+                    continue;
+                };
+
+                if adjusted_pos + rule.pattern.len() > nodes.len() {
+                    continue;
+                }
+
+                nodes.drain(adjusted_pos..adjusted_pos + rule.pattern.len());
+
+                let replacement = rule.node_apply(&bindings);
+                for (i, node) in replacement.iter().enumerate() {
+                    nodes.insert(adjusted_pos + i, node.clone());
+                }
+
+                offset += replacement.len() as i128 - rule.pattern.len() as i128;
+            }
+        }
+        nodes
+    }
 }
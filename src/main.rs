@@ -1,319 +1,30 @@
 mod data;
 mod formatting;
 
-use data::{QuoteStyle, Token};
+use data::{Diagnostic, Severity};
 
-use std::collections::HashMap;
-use std::iter::Peekable;
+use std::collections::HashSet;
 use std::path::PathBuf;
 use std::rc::Rc;
-use std::str::Chars;
 use std::{cell::RefCell, io::Read};
 
 use clap::Parser;
 use formatting::build_engine;
-use rhai::Scope;
+use rhai::{Dynamic, Map, Scope};
 use serde::{Deserialize, Serialize};
-use stringlit::s;
+use toresy::{build_tree, flatten, LexerConfig, Rewriter, Rule, Token, Tokenizer, TreeError};
 
 const SEPARATOR: &str = "----------------------------------------";
 
-#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
-enum QuoteStylePattern {
-    Single,
-    Double,
-    Any,
-}
-
-#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
-enum Pattern {
-    Identifier(String),                   // Matches a specific identifier
-    Number(String),                       // Matches a specific number
-    AnyIdentifier(String),                // Matches any identifier and binds it
-    AnyNumber(String),                    // Matches any number and binds it
-    Symbol(String),                       // Matches a specific symbol
-    OpenParen(String),                    // Matches an open parenthesis
-    CloseParen(String),                   // Matches a close parenthesis
-    String(String, QuoteStylePattern),    // Matches a specific string
-    AnyString(String, QuoteStylePattern), // Matches any string and binds it
-    Any,                                  // Matches any single token
-}
-
-struct Tokenizer<'a> {
-    input: Peekable<Chars<'a>>,
-}
-
-impl<'a> Tokenizer<'a> {
-    fn new(input: &'a str) -> Self {
-        Self {
-            input: input.chars().peekable(),
-        }
-    }
-
-    fn next_token(&mut self) -> Option<Token> {
-        while let Some(&ch) = self.input.peek() {
-            match ch {
-                ' ' | '\t' | '\n' | '\r' => {
-                    self.input.next(); // Skip whitespace
-                }
-                '(' => {
-                    self.input.next();
-                    return Some(Token::OpenParen(s!("(")));
-                }
-                ')' => {
-                    self.input.next();
-                    return Some(Token::CloseParen(s!(")")));
-                }
-                '0'..='9' => {
-                    return Some(self.consume_number());
-                }
-                'a'..='z' | 'A'..='Z' | '_' => {
-                    return Some(self.consume_identifier());
-                }
-                '\'' => {
-                    return Some(self.consume_string(QuoteStyle::Single));
-                }
-                '"' => {
-                    return Some(self.consume_string(QuoteStyle::Double));
-                }
-                _ => {
-                    return Some(self.consume_symbol());
-                }
-            }
-        }
-        None
-    }
-
-    fn consume_number(&mut self) -> Token {
-        let mut number = String::new();
-        while let Some(&ch) = self.input.peek() {
-            if ch.is_numeric() {
-                number.push(ch);
-                self.input.next();
-            } else {
-                break;
-            }
-        }
-        Token::Number(number)
-    }
-
-    fn consume_identifier(&mut self) -> Token {
-        let mut identifier = String::new();
-        while let Some(&ch) = self.input.peek() {
-            if ch.is_alphanumeric() || ch == '_' {
-                identifier.push(ch);
-                self.input.next();
-            } else {
-                break;
-            }
-        }
-        Token::Identifier(identifier)
-    }
-
-    fn consume_string(&mut self, quote_style: QuoteStyle) -> Token {
-        let quote = self.input.next().unwrap(); // Consume the opening quote
-        let mut string_content = String::new();
-
-        while let Some(&ch) = self.input.peek() {
-            if ch == quote {
-                self.input.next(); // Consume the closing quote
-                return Token::StringLiteral(string_content, quote_style);
-            }
-            string_content.push(ch);
-            self.input.next();
-        }
-
-        panic!("Unterminated string literal");
-    }
-
-    fn consume_symbol(&mut self) -> Token {
-        let mut symbol = String::new();
-        while let Some(&ch) = self.input.peek() {
-            if ch.is_alphanumeric()
-                || ch.is_whitespace()
-                || ch == '('
-                || ch == ')'
-                || ch == '\''
-                || ch == '"'
-            {
-                break;
-            }
-            symbol.push(ch);
-            self.input.next();
-        }
-        Token::Symbol(symbol)
-    }
-}
-
+/// The top-level shape of a rules file: an optional lexer-config header declaring comment
+/// syntax and keywords, followed by the list of rewrite rules.
 #[derive(Debug, Serialize, Deserialize)]
-struct Rule {
-    pattern: Vec<Pattern>,
-    replacement: Vec<Token>,
-}
-
-impl Rule {
-    fn will_match(&self, tokens: &[Token]) -> bool {
-        for (pattern, token) in self.pattern.iter().zip(tokens) {
-            match (pattern, token) {
-                (Pattern::Identifier(p), Token::Identifier(t))
-                | (Pattern::Number(p), Token::Number(t))
-                | (Pattern::Symbol(p), Token::Symbol(t))
-                | (Pattern::OpenParen(p), Token::OpenParen(t))
-                | (Pattern::CloseParen(p), Token::CloseParen(t))
-                | (
-                    Pattern::String(p, QuoteStylePattern::Double),
-                    Token::StringLiteral(t, QuoteStyle::Double),
-                )
-                | (
-                    Pattern::String(p, QuoteStylePattern::Single),
-                    Token::StringLiteral(t, QuoteStyle::Single),
-                )
-                | (Pattern::String(p, QuoteStylePattern::Any), Token::StringLiteral(t, _))
-                    if p == t || p == "*" => {}
-                (Pattern::AnyIdentifier(_), Token::Identifier(_))
-                | (Pattern::AnyNumber(_), Token::Number(_))
-                | (
-                    Pattern::AnyString(_, QuoteStylePattern::Double),
-                    Token::StringLiteral(_, QuoteStyle::Double),
-                )
-                | (
-                    Pattern::AnyString(_, QuoteStylePattern::Single),
-                    Token::StringLiteral(_, QuoteStyle::Single),
-                )
-                | (Pattern::AnyString(_, QuoteStylePattern::Any), Token::StringLiteral(_, _))
-                | (Pattern::Any, _) => {}
-                _ => return false,
-            }
-        }
-
-        true
-    }
-
-    fn matches(&self, tokens: &[Token]) -> Vec<(HashMap<String, Token>, usize)> {
-        let mut result = Vec::new();
-
-        if tokens.len() < self.pattern.len() {
-            return result;
-        }
-
-        let diff = tokens.len() - self.pattern.len() + 1;
-        for i in 0..=diff {
-            if !self.will_match(&tokens[i..]) {
-                continue;
-            }
-            let mut bindings = HashMap::new();
-            for (pattern, token) in self.pattern.iter().zip(tokens[i..].iter()) {
-                match (pattern, token) {
-                    (Pattern::Identifier(p), Token::Identifier(t)) if p == t => {}
-                    (Pattern::Number(p), Token::Number(t)) if p == t => {}
-                    (Pattern::AnyIdentifier(name), Token::Identifier(t)) => {
-                        bindings.insert(name.clone(), Token::Identifier(t.clone()));
-                    }
-                    (Pattern::AnyNumber(name), Token::Number(t)) => {
-                        bindings.insert(name.clone(), Token::Number(t.clone()));
-                    }
-                    (Pattern::Symbol(p), Token::Symbol(t)) if p == t => {}
-                    (Pattern::OpenParen(pp), Token::OpenParen(pt)) if pp == pt || pp == "*" => {}
-                    (Pattern::CloseParen(pp), Token::CloseParen(pt)) if pp == pt || pp == "*" => {}
-                    (
-                        Pattern::String(ps, QuoteStylePattern::Double),
-                        Token::StringLiteral(ts, QuoteStyle::Double),
-                    ) if ps == ts => {}
-                    (
-                        Pattern::String(ps, QuoteStylePattern::Single),
-                        Token::StringLiteral(ts, QuoteStyle::Single),
-                    ) if ps == ts => {}
-                    (Pattern::String(ps, QuoteStylePattern::Any), Token::StringLiteral(ts, _))
-                        if ps == ts => {}
-                    (
-                        Pattern::AnyString(name, QuoteStylePattern::Double),
-                        Token::StringLiteral(t, QuoteStyle::Double),
-                    ) => {
-                        bindings.insert(
-                            name.clone(),
-                            Token::StringLiteral(t.clone(), QuoteStyle::Double),
-                        );
-                    }
-                    (
-                        Pattern::AnyString(name, QuoteStylePattern::Single),
-                        Token::StringLiteral(t, QuoteStyle::Single),
-                    ) => {
-                        bindings.insert(
-                            name.clone(),
-                            Token::StringLiteral(t.clone(), QuoteStyle::Single),
-                        );
-                    }
-                    (
-                        Pattern::AnyString(name, QuoteStylePattern::Any),
-                        Token::StringLiteral(t, sl),
-                    ) => {
-                        bindings.insert(name.clone(), Token::StringLiteral(t.clone(), sl.clone()));
-                    }
-                    (Pattern::Any, t) => {
-                        bindings.insert(s!("_"), t.clone());
-                    }
-                    _ => {}
-                }
-            }
-            result.push((bindings, i));
-        }
-        result
-    }
-
-    fn apply(&self, bindings: &HashMap<String, Token>) -> Vec<Token> {
-        self.replacement
-            .iter()
-            .map(|token| match token {
-                Token::Identifier(id) => bindings.get(id).cloned().unwrap_or_else(|| token.clone()),
-                Token::Number(num) => bindings.get(num).cloned().unwrap_or_else(|| token.clone()),
-                Token::Symbol(sym) => bindings.get(sym).cloned().unwrap_or_else(|| token.clone()),
-                Token::StringLiteral(str, _) => {
-                    bindings.get(str).cloned().unwrap_or_else(|| token.clone())
-                }
-                _ => token.clone(),
-            })
-            .collect()
-    }
-}
-
-struct Rewriter {
+struct RulesFile {
+    #[serde(default)]
+    lexer: LexerConfig,
     rules: Vec<Rule>,
 }
 
-impl Rewriter {
-    fn new(rules: Vec<Rule>) -> Self {
-        Self { rules }
-    }
-
-    fn rewrite(&self, mut tokens: Vec<Token>) -> Vec<Token> {
-        for rule in &self.rules {
-            let mut offset: i128 = 0;
-            for (bindings, match_pos) in rule.matches(&tokens) {
-                // Adjust the position based on the current offset
-                let adjusted_pos = usize::try_from(match_pos as i128 + offset).unwrap();
-
-                // Ensure the position is valid
-                if adjusted_pos + rule.pattern.len() > tokens.len() {
-                    continue;
-                }
-
-                // Remove the matched tokens
-                tokens.drain(adjusted_pos..adjusted_pos + rule.pattern.len());
-
-                // Insert the replacement tokens
-                let replacement = rule.apply(&bindings);
-                for (i, token) in replacement.iter().enumerate() {
-                    tokens.insert(adjusted_pos + i, token.clone());
-                }
-
-                // Update the offset based on the size difference
-                offset += replacement.len() as i128 - rule.pattern.len() as i128;
-            }
-        }
-        tokens
-    }
-}
-
 #[derive(Parser)]
 struct Args {
     input: Option<String>,
@@ -332,6 +43,32 @@ struct Args {
 
     #[arg(long, short)]
     verbose: bool,
+
+    /// Serialize the collected diagnostics as JSON instead of plain text
+    #[arg(long)]
+    json: bool,
+
+    /// Disable filesystem access (exists/is_file/is_dir/read_file/glob) for sandboxed runs
+    #[arg(long)]
+    no_fs: bool,
+
+    /// Load the carried-over `state` map (as produced by --state-out) from a JSON file
+    #[arg(long)]
+    state_in: Option<PathBuf>,
+
+    /// Dump the final `state` map to a JSON file for reuse in a later run
+    #[arg(long)]
+    state_out: Option<PathBuf>,
+
+    /// On a lexing error, skip to the next whitespace boundary and keep tokenizing instead
+    /// of stopping at the first error
+    #[arg(long)]
+    continue_on_error: bool,
+
+    /// Rewrite over a balanced-paren Node tree instead of the flat token stream, so rules
+    /// with Pattern::Group/Pattern::AnyGroup can match and rewrite nested subexpressions
+    #[arg(long)]
+    tree: bool,
 }
 
 fn main() {
@@ -342,13 +79,27 @@ fn main() {
         format,
         debug,
         verbose,
+        json,
+        no_fs,
+        state_in,
+        state_out,
+        continue_on_error,
+        tree,
     } = Args::parse();
 
     let messages = Rc::new(RefCell::new(Vec::new()));
+    let rule_names: Rc<RefCell<HashSet<String>>> = Rc::new(RefCell::new(HashSet::new()));
+
+    let initial_state = state_in.map_or_else(Map::new, |path| {
+        let raw = std::fs::read_to_string(path).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&raw).unwrap();
+        rhai::serde::to_dynamic(value).unwrap().cast::<Map>()
+    });
+    let state: Rc<RefCell<Map>> = Rc::new(RefCell::new(initial_state));
 
     let rules = std::fs::read_to_string(rules).unwrap();
 
-    let rules: Vec<Rule> = serde_lexpr::from_str(&rules).unwrap();
+    let RulesFile { lexer, rules }: RulesFile = serde_lexpr::from_str(&rules).unwrap();
     if verbose {
         eprintln!("Rules:");
         eprintln!("{}", serde_yaml::to_string(&rules).unwrap());
@@ -362,11 +113,30 @@ fn main() {
         String::from_utf8(buf).unwrap()
     });
 
-    let mut tokenizer = Tokenizer::new(&input);
+    let mut tokenizer = Tokenizer::with_config(&input, lexer);
     let mut tokens = Vec::new();
+    let mut lex_errors = Vec::new();
+
+    loop {
+        match tokenizer.next_token() {
+            Ok(Some(token)) => tokens.push(token),
+            Ok(None) => break,
+            Err(err) => {
+                lex_errors.push(err);
+                if continue_on_error {
+                    tokenizer.skip_to_boundary();
+                } else {
+                    break;
+                }
+            }
+        }
+    }
 
-    while let Some(token) = tokenizer.next_token() {
-        tokens.push(token);
+    if !lex_errors.is_empty() {
+        for err in &lex_errors {
+            eprintln!("error: {err}");
+        }
+        std::process::exit(1);
     }
 
     if verbose {
@@ -376,7 +146,15 @@ fn main() {
         eprintln!("Tokens:");
         eprintln!("{}", serde_yaml::to_string(&tokens).unwrap().trim());
     }
-    let rewritten_tokens = rewriter.rewrite(tokens);
+    let rewritten_tokens = if tree {
+        let nodes = build_tree(tokens).unwrap_or_else(|err: TreeError| {
+            eprintln!("error: {err}");
+            std::process::exit(1);
+        });
+        flatten(rewriter.rewrite_tree(nodes))
+    } else {
+        rewriter.rewrite(tokens)
+    };
     if verbose {
         eprintln!();
         eprintln!("{SEPARATOR}");
@@ -399,17 +177,49 @@ fn main() {
         scope.push_constant("OpenParen", "OpenParen");
         scope.push_constant("CloseParen", "CloseParen");
         scope.push_constant("StringLiteral", "StringLiteral");
-        let engine = build_engine(messages.clone(), debug);
-        engine.run_with_scope(&mut scope, &script).unwrap();
+        scope.push_constant("Keyword", "Keyword");
+        let engine = build_engine(
+            messages.clone(),
+            rule_names.clone(),
+            state.clone(),
+            debug,
+            !no_fs,
+        );
+        if let Err(err) = engine.run_with_scope(&mut scope, &script) {
+            eprintln!("error: {err}");
+            std::process::exit(1);
+        }
+
+        if verbose {
+            eprintln!();
+            eprintln!("{SEPARATOR}");
+            eprintln!();
+            eprintln!("Registered rules:");
+            eprintln!(
+                "{}",
+                rule_names
+                    .borrow()
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        if let Some(ref state_out) = state_out {
+            let value: serde_json::Value =
+                rhai::serde::from_dynamic(&Dynamic::from(state.borrow().clone())).unwrap();
+            std::fs::write(state_out, serde_json::to_string_pretty(&value).unwrap()).unwrap();
+        }
     } else {
         let mut messages = messages.borrow_mut();
         messages.clear();
-        messages.push(
+        messages.push(Diagnostic::info(
             serde_lexpr::to_string(&rewritten_tokens)
                 .unwrap()
                 .trim()
                 .to_owned(),
-        );
+        ));
     }
 
     if verbose {
@@ -418,7 +228,16 @@ fn main() {
         eprintln!();
     }
 
-    let text = messages.borrow().join("");
+    let text = if json {
+        serde_json::to_string(&*messages.borrow()).unwrap()
+    } else {
+        messages
+            .borrow()
+            .iter()
+            .map(|d| d.message.as_str())
+            .collect::<Vec<_>>()
+            .join("")
+    };
 
     match output {
         Some(ref output) => {